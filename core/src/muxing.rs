@@ -0,0 +1,269 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Muxing is the process of splitting a connection into multiple substreams.
+//!
+//! The main item of this module is the `StreamMuxer` trait, which is implemented on objects that
+//! can be used to manage substreams over a connection. `StreamMuxer` is generic over the
+//! substream identifiers and behaves similarly to a collection.
+
+use crate::Multiaddr;
+use futures::prelude::*;
+use std::{fmt, io::Error as IoError, ops::Deref, pin::Pin, task::Context, task::Poll};
+
+/// Implemented on objects that can open and manage substreams.
+pub trait StreamMuxer {
+    /// Type of the object that represents the raw substream where data can be read and written.
+    type Substream;
+
+    /// Type of the object that represents an outbound substream being opened.
+    type OutboundSubstream;
+
+    /// Error type of the muxer.
+    type Error: Into<IoError>;
+
+    /// Polls for a connection-wide event.
+    ///
+    /// This function behaves the same as a `Stream`.
+    ///
+    /// If `Poll::Pending` is returned, then the current task will be notified once the muxer
+    /// is ready to be polled, similar to the API of `Stream::poll_next()`.
+    /// Only the latest task that was used to call this method may be notified.
+    ///
+    /// It is permissible and common to use this method to perform background
+    /// work, such as processing incoming packets and polling for timeouts.
+    fn poll(
+        &self,
+        cx: &mut Context,
+    ) -> Poll<Result<StreamMuxerEvent<Self::Substream>, Self::Error>>;
+
+    /// Opens a new outbound substream.
+    ///
+    /// Note that the returned future may not resolve to success, for example if the maximum
+    /// number of substreams has been reached.
+    fn open_outbound(&self) -> Self::OutboundSubstream;
+
+    /// Polls the outbound substream.
+    ///
+    /// May panic or produce an undefined result if the outbound substream has already been
+    /// successfully polled by a previous call to this method.
+    fn poll_outbound(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::OutboundSubstream,
+    ) -> Poll<Result<Self::Substream, Self::Error>>;
+
+    /// Destroys an outbound substream. Use this after the outbound substream has finished, or
+    /// if you want to interrupt it.
+    fn destroy_outbound(&self, s: Self::OutboundSubstream);
+
+    /// Reads data from a substream. The behaviour is the same as `futures::AsyncRead::poll_read`.
+    fn read_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>>;
+
+    /// Write data to a substream. The behaviour is the same as `futures::AsyncWrite::poll_write`.
+    fn write_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>>;
+
+    /// Flushes data on a substream. The behaviour is the same as `futures::AsyncWrite::poll_flush`.
+    fn flush_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>>;
+
+    /// Attempts to shut down the writing side of a substream. The behaviour is the same as
+    /// `futures::AsyncWrite::poll_close`.
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>>;
+
+    /// Destroys a substream.
+    fn destroy_substream(&self, s: Self::Substream);
+
+    /// Returns `true` if the remote has shown any sign of activity after the muxer has been
+    /// open.
+    ///
+    /// This is primarily used for knowing whether an outbound substream can still reasonably be
+    /// attempted, or whether the connection should be considered as good as dead.
+    fn is_remote_acknowledged(&self) -> bool;
+
+    /// Closes this `StreamMuxer`.
+    ///
+    /// After this has returned `Poll::Ready(Ok(()))`, all the substreams should be closed and
+    /// this function should not be called again.
+    fn close(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>>;
+
+    /// Flushes this `StreamMuxer`.
+    ///
+    /// This drains any write buffers of substreams, but unlike `close` it keeps the substreams
+    /// usable afterwards.
+    fn flush_all(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>>;
+}
+
+/// Event about a connection, reported by a `StreamMuxer`.
+#[derive(Debug, Clone)]
+pub enum StreamMuxerEvent<S> {
+    /// A new inbound substream arrived.
+    InboundSubstream(S),
+
+    /// The address of the remote has changed, for example after a NAT rebinding or a QUIC
+    /// connection migration.
+    AddressChange(Multiaddr),
+}
+
+impl<S> StreamMuxerEvent<S> {
+    /// Returns the inbound substream if this is an `InboundSubstream`, or `None` otherwise.
+    pub fn into_inbound_substream(self) -> Option<S> {
+        match self {
+            StreamMuxerEvent::InboundSubstream(s) => Some(s),
+            StreamMuxerEvent::AddressChange(_) => None,
+        }
+    }
+}
+
+/// Abstracts a substream produced by a `StreamMuxer`, holding a reference to the muxer so that
+/// it can be read, written, and destroyed without the caller having to keep the muxer around
+/// separately.
+pub struct SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    muxer: P,
+    substream: Option<<P::Target as StreamMuxer>::Substream>,
+}
+
+impl<P> fmt::Debug for SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubstreamRef").finish()
+    }
+}
+
+impl<P> Drop for SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    fn drop(&mut self) {
+        if let Some(substream) = self.substream.take() {
+            self.muxer.destroy_substream(substream);
+        }
+    }
+}
+
+impl<P> AsyncRead for SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let this = self.get_mut();
+        this.muxer
+            .read_substream(
+                cx,
+                this.substream
+                    .as_mut()
+                    .expect("substream already destroyed"),
+                buf,
+            )
+            .map_err(Into::into)
+    }
+}
+
+impl<P> AsyncWrite for SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let this = self.get_mut();
+        this.muxer
+            .write_substream(
+                cx,
+                this.substream
+                    .as_mut()
+                    .expect("substream already destroyed"),
+                buf,
+            )
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        this.muxer
+            .flush_substream(
+                cx,
+                this.substream
+                    .as_mut()
+                    .expect("substream already destroyed"),
+            )
+            .map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        this.muxer
+            .shutdown_substream(
+                cx,
+                this.substream
+                    .as_mut()
+                    .expect("substream already destroyed"),
+            )
+            .map_err(Into::into)
+    }
+}
+
+/// Builds a `SubstreamRef` from a reference to a muxer and a substream produced by that muxer.
+pub fn substream_from_ref<P>(
+    muxer: P,
+    substream: <P::Target as StreamMuxer>::Substream,
+) -> SubstreamRef<P>
+where
+    P: Deref,
+    P::Target: StreamMuxer,
+{
+    SubstreamRef {
+        muxer,
+        substream: Some(substream),
+    }
+}
@@ -18,10 +18,14 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use crate::muxing;
+use crate::muxing::{self, StreamMuxerEvent};
+use crate::Multiaddr;
 use futures::prelude::*;
 use smallvec::SmallVec;
-use std::{fmt, io::Error as IoError, pin::Pin, sync::Arc, task::Context, task::Poll};
+use std::{
+    collections::VecDeque, fmt, io::Error as IoError, pin::Pin, sync::Arc, task::Context,
+    task::Poll,
+};
 
 // Implementation notes
 // =================
@@ -52,16 +56,48 @@ where
 {
     /// The muxer used to manage substreams.
     muxer: Arc<TMuxer>,
-    /// List of substreams we are currently opening.
-    outbound_substreams: SmallVec<[(TUserData, TMuxer::OutboundSubstream); 8]>,
+    /// List of substreams we are currently opening, identified by the `OutboundSubstreamId`
+    /// that was returned to the caller when the substream was requested.
+    outbound_substreams: SmallVec<[(OutboundSubstreamId, TUserData, TMuxer::OutboundSubstream); 8]>,
+    /// Identifier to assign to the next outbound substream.
+    next_outbound_substream_id: OutboundSubstreamId,
+    /// Number of inbound substreams that have been produced as a `NodeEvent::InboundSubstream`
+    /// but not yet acknowledged by the consumer through `acknowledge_inbound`.
+    pending_inbound: usize,
+    /// Maximum number of inbound substreams allowed to be pending acknowledgement at once.
+    /// Once reached, newly-accepted inbound substreams are held in `buffered_inbound` instead
+    /// of being turned into a `NodeEvent` straight away, up to the same limit. The muxer itself
+    /// is always polled regardless of this limit, so that connection-wide events such as
+    /// `AddressChange` and errors are never starved by a consumer that is behind on
+    /// acknowledging substreams.
+    max_pending_inbound: usize,
+    /// Inbound substreams that the muxer has produced while we were over `max_pending_inbound`,
+    /// waiting to be turned into a `NodeEvent::InboundSubstream` once the consumer acknowledges
+    /// enough of the substreams it already has.
+    ///
+    /// Bounded by `max_pending_inbound`: once both the pending and the buffered count reach it,
+    /// any further inbound substream the muxer produces is destroyed straight away instead of
+    /// being queued, so a remote cannot use this buffer to make us accumulate an unbounded
+    /// number of substream objects.
+    buffered_inbound: VecDeque<TMuxer::Substream>,
 }
 
+/// Default value of [`NodeStream::max_pending_inbound`] used by [`NodeStream::new`].
+const DEFAULT_MAX_PENDING_INBOUND: usize = 4;
+
 /// Future that signals the remote that we have closed the connection.
 pub struct Close<TMuxer> {
     /// Muxer to close.
     muxer: Arc<TMuxer>,
 }
 
+/// Future that flushes the buffered writes of all the substreams of a connection, without
+/// closing it.
+pub struct Flush<TMuxer> {
+    /// Muxer to flush.
+    muxer: Arc<TMuxer>,
+}
+
 /// A successfully opened substream.
 pub type Substream<TMuxer> = muxing::SubstreamRef<Arc<TMuxer>>;
 
@@ -79,18 +115,35 @@ where
 
     /// An outbound substream has successfully been opened.
     OutboundSubstream {
+        /// Identifier of the substream, as returned by `open_substream`.
+        id: OutboundSubstreamId,
         /// User data that has been passed to the `open_substream` method.
         user_data: TUserData,
         /// The newly-opened substream. Will return EOF of an error if the `NodeStream` is
         /// destroyed or `close_graceful` is called.
         substream: Substream<TMuxer>,
     },
+
+    /// The address of the remote has changed, for example after a NAT rebinding or a QUIC
+    /// connection migration.
+    AddressChange {
+        /// The new address of the remote.
+        new_address: Multiaddr,
+    },
 }
 
 /// Identifier for a substream being opened.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct OutboundSubstreamId(usize);
 
+impl OutboundSubstreamId {
+    fn next(&mut self) -> OutboundSubstreamId {
+        let id = *self;
+        self.0 += 1;
+        id
+    }
+}
+
 impl<TMuxer, TUserData> NodeStream<TMuxer, TUserData>
 where
     TMuxer: muxing::StreamMuxer,
@@ -101,17 +154,58 @@ where
         NodeStream {
             muxer: Arc::new(muxer),
             outbound_substreams: SmallVec::new(),
+            next_outbound_substream_id: OutboundSubstreamId(0),
+            pending_inbound: 0,
+            max_pending_inbound: DEFAULT_MAX_PENDING_INBOUND,
+            buffered_inbound: VecDeque::new(),
         }
     }
 
+    /// Sets the maximum number of inbound substreams that may be pending acknowledgement
+    /// before new ones stop being turned into `NodeEvent::InboundSubstream`.
+    ///
+    /// The muxer keeps being polled once this limit is reached: further inbound substreams are
+    /// held in a buffer of the same size, and any that arrive while that buffer is full are
+    /// destroyed. See `acknowledge_inbound`.
+    pub fn set_max_pending_inbound(&mut self, max_pending_inbound: usize) {
+        self.max_pending_inbound = max_pending_inbound;
+    }
+
+    /// Acknowledges that one previously-produced `InboundSubstream` event has been consumed,
+    /// allowing one more inbound substream to be turned into a `NodeEvent` on the next `poll`,
+    /// either straight from the muxer or from `buffered_inbound` if one is already waiting there.
+    pub fn acknowledge_inbound(&mut self) {
+        self.pending_inbound = self.pending_inbound.saturating_sub(1);
+    }
+
     /// Starts the process of opening a new outbound substream.
     ///
     /// After calling this method, polling the stream should eventually produce either an
     /// `OutboundSubstream` event or an `OutboundClosed` event containing the user data that has
     /// been passed to this method.
-    pub fn open_substream(&mut self, user_data: TUserData) {
+    ///
+    /// Returns an identifier that can later be passed to `cancel_substream` to abort this
+    /// particular substream attempt without disturbing any other pending outbound substream.
+    pub fn open_substream(&mut self, user_data: TUserData) -> OutboundSubstreamId {
+        let id = self.next_outbound_substream_id.next();
         let raw = self.muxer.open_outbound();
-        self.outbound_substreams.push((user_data, raw));
+        self.outbound_substreams.push((id, user_data, raw));
+        id
+    }
+
+    /// Destroys a single pending outbound substream, as identified by the `OutboundSubstreamId`
+    /// returned from `open_substream`, and returns the user data that had been passed to it.
+    ///
+    /// Returns `None` if no pending outbound substream with this identifier exists, for example
+    /// because it has already completed or been cancelled.
+    pub fn cancel_substream(&mut self, id: OutboundSubstreamId) -> Option<TUserData> {
+        let pos = self
+            .outbound_substreams
+            .iter()
+            .position(|(s_id, _, _)| *s_id == id)?;
+        let (_, user_data, outbound) = self.outbound_substreams.remove(pos);
+        self.muxer.destroy_outbound(outbound);
+        Some(user_data)
     }
 
     /// Returns `true` if the remote has shown any sign of activity after the muxer has been open.
@@ -132,10 +226,21 @@ where
         (close, substreams)
     }
 
+    /// Returns a future that flushes the buffered writes of all the substreams of this
+    /// connection, without closing them.
+    ///
+    /// Unlike `close`, this does not consume the `NodeStream` and the existing substreams
+    /// remain usable once the returned future resolves.
+    pub fn flush_all(&self) -> Flush<TMuxer> {
+        Flush {
+            muxer: self.muxer.clone(),
+        }
+    }
+
     /// Destroys all outbound streams and returns the corresponding user data.
     pub fn cancel_outgoing(&mut self) -> Vec<TUserData> {
         let mut out = Vec::with_capacity(self.outbound_substreams.len());
-        for (user_data, outbound) in self.outbound_substreams.drain(..) {
+        for (_id, user_data, outbound) in self.outbound_substreams.drain(..) {
             out.push(user_data);
             self.muxer.destroy_outbound(outbound);
         }
@@ -147,12 +252,42 @@ where
         &mut self,
         cx: &mut Context,
     ) -> Poll<Result<NodeEvent<TMuxer, TUserData>, IoError>> {
-        // Polling inbound substream.
-        match self.muxer.poll_inbound(cx) {
-            Poll::Ready(Ok(substream)) => {
+        // If we already have a buffered inbound substream and the consumer has caught up with
+        // acknowledging the ones it has, hand it over before looking at the muxer again.
+        if self.pending_inbound < self.max_pending_inbound {
+            if let Some(substream) = self.buffered_inbound.pop_front() {
+                self.pending_inbound += 1;
                 let substream = muxing::substream_from_ref(self.muxer.clone(), substream);
                 return Poll::Ready(Ok(NodeEvent::InboundSubstream { substream }));
             }
+        }
+
+        // Polling connection-wide events. This is always done, regardless of the backpressure
+        // applied to inbound substreams below, so that `AddressChange` and errors are never
+        // starved by a consumer that is behind on acknowledging substreams it already has.
+        match self.muxer.poll(cx) {
+            Poll::Ready(Ok(StreamMuxerEvent::InboundSubstream(substream))) => {
+                if self.pending_inbound < self.max_pending_inbound {
+                    self.pending_inbound += 1;
+                    let substream = muxing::substream_from_ref(self.muxer.clone(), substream);
+                    return Poll::Ready(Ok(NodeEvent::InboundSubstream { substream }));
+                } else if self.buffered_inbound.len() < self.max_pending_inbound {
+                    // The consumer hasn't acknowledged enough of its pending inbound substreams
+                    // yet; hold onto this one until it does instead of raising it now.
+                    self.buffered_inbound.push_back(substream);
+                    // The muxer returned `Ready`, so it has not registered our task.
+                    cx.waker().wake_by_ref();
+                } else {
+                    // Both the pending and the buffered limits are reached: the remote is
+                    // outpacing what the consumer can handle. Drop this substream rather than
+                    // let `buffered_inbound` grow without bound.
+                    self.muxer.destroy_substream(substream);
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::Ready(Ok(StreamMuxerEvent::AddressChange(new_address))) => {
+                return Poll::Ready(Ok(NodeEvent::AddressChange { new_address }));
+            }
             Poll::Ready(Err(err)) => return Poll::Ready(Err(err.into())),
             Poll::Pending => {}
         }
@@ -160,18 +295,19 @@ where
         // Polling outbound substreams.
         // We remove each element from `outbound_substreams` one by one and add them back.
         for n in (0..self.outbound_substreams.len()).rev() {
-            let (user_data, mut outbound) = self.outbound_substreams.swap_remove(n);
+            let (id, user_data, mut outbound) = self.outbound_substreams.swap_remove(n);
             match self.muxer.poll_outbound(cx, &mut outbound) {
                 Poll::Ready(Ok(substream)) => {
                     let substream = muxing::substream_from_ref(self.muxer.clone(), substream);
                     self.muxer.destroy_outbound(outbound);
                     return Poll::Ready(Ok(NodeEvent::OutboundSubstream {
+                        id,
                         user_data,
                         substream,
                     }));
                 }
                 Poll::Pending => {
-                    self.outbound_substreams.push((user_data, outbound));
+                    self.outbound_substreams.push((id, user_data, outbound));
                 }
                 Poll::Ready(Err(err)) => {
                     self.muxer.destroy_outbound(outbound);
@@ -204,9 +340,12 @@ where
         // The substreams that were produced will continue to work, as the muxer is held in an Arc.
         // However we will no longer process any further inbound or outbound substream, and we
         // therefore close everything.
-        for (_, outbound) in self.outbound_substreams.drain(..) {
+        for (_, _, outbound) in self.outbound_substreams.drain(..) {
             self.muxer.destroy_outbound(outbound);
         }
+        for substream in self.buffered_inbound.drain(..) {
+            self.muxer.destroy_substream(substream);
+        }
     }
 }
 
@@ -234,6 +373,30 @@ where
     }
 }
 
+impl<TMuxer> Future for Flush<TMuxer>
+where
+    TMuxer: muxing::StreamMuxer,
+{
+    type Output = Result<(), IoError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.muxer.flush_all(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err.into())),
+        }
+    }
+}
+
+impl<TMuxer> fmt::Debug for Flush<TMuxer>
+where
+    TMuxer: muxing::StreamMuxer,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Flush").finish()
+    }
+}
+
 impl<TMuxer, TUserData> fmt::Debug for NodeEvent<TMuxer, TUserData>
 where
     TMuxer: muxing::StreamMuxer,
@@ -247,13 +410,203 @@ where
                 .field("substream", substream)
                 .finish(),
             NodeEvent::OutboundSubstream {
+                id,
                 user_data,
                 substream,
             } => f
                 .debug_struct("NodeEvent::OutboundSubstream")
+                .field("id", id)
                 .field("user_data", user_data)
                 .field("substream", substream)
                 .finish(),
+            NodeEvent::AddressChange { new_address } => f
+                .debug_struct("NodeEvent::AddressChange")
+                .field("new_address", new_address)
+                .finish(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use std::sync::Mutex;
+
+    /// A `StreamMuxer` whose inbound substreams are fed by the test and whose outbound substreams
+    /// never complete, so that `NodeStream`'s bookkeeping can be exercised deterministically.
+    #[derive(Default)]
+    struct FakeMuxer {
+        /// Inbound substreams waiting to be yielded by `poll`, fed by the test.
+        inbound: Arc<Mutex<VecDeque<usize>>>,
+        /// Substreams that have been passed to `destroy_substream`, recorded for assertions.
+        destroyed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl muxing::StreamMuxer for FakeMuxer {
+        type Substream = usize;
+        type OutboundSubstream = ();
+        type Error = IoError;
+
+        fn poll(&self, _: &mut Context) -> Poll<Result<StreamMuxerEvent<usize>, IoError>> {
+            match self.inbound.lock().unwrap().pop_front() {
+                Some(id) => Poll::Ready(Ok(StreamMuxerEvent::InboundSubstream(id))),
+                None => Poll::Pending,
+            }
+        }
+
+        fn open_outbound(&self) {}
+
+        fn poll_outbound(&self, _: &mut Context, _: &mut ()) -> Poll<Result<usize, IoError>> {
+            Poll::Pending
+        }
+
+        fn destroy_outbound(&self, _: ()) {}
+
+        fn read_substream(
+            &self,
+            _: &mut Context,
+            _: &mut usize,
+            _: &mut [u8],
+        ) -> Poll<Result<usize, IoError>> {
+            Poll::Pending
+        }
+
+        fn write_substream(
+            &self,
+            _: &mut Context,
+            _: &mut usize,
+            _: &[u8],
+        ) -> Poll<Result<usize, IoError>> {
+            Poll::Pending
+        }
+
+        fn flush_substream(&self, _: &mut Context, _: &mut usize) -> Poll<Result<(), IoError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn shutdown_substream(&self, _: &mut Context, _: &mut usize) -> Poll<Result<(), IoError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn destroy_substream(&self, s: usize) {
+            self.destroyed.lock().unwrap().push(s);
+        }
+
+        fn is_remote_acknowledged(&self) -> bool {
+            true
+        }
+
+        fn close(&self, _: &mut Context) -> Poll<Result<(), IoError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn flush_all(&self, _: &mut Context) -> Poll<Result<(), IoError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_once(
+        node: &mut NodeStream<FakeMuxer, u32>,
+    ) -> Poll<Result<NodeEvent<FakeMuxer, u32>, IoError>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        node.poll(&mut cx)
+    }
+
+    #[test]
+    fn acknowledge_inbound_unblocks_backpressure() {
+        let inbound = Arc::new(Mutex::new(VecDeque::new()));
+        let muxer = FakeMuxer {
+            inbound: inbound.clone(),
+            destroyed: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut node = NodeStream::new(muxer);
+        node.set_max_pending_inbound(2);
+        inbound.lock().unwrap().extend([1, 2, 3]);
+
+        match poll_once(&mut node) {
+            Poll::Ready(Ok(NodeEvent::InboundSubstream { .. })) => {}
+            other => panic!(
+                "expected first inbound substream, got {:?}",
+                other.is_ready()
+            ),
+        }
+        match poll_once(&mut node) {
+            Poll::Ready(Ok(NodeEvent::InboundSubstream { .. })) => {}
+            other => panic!(
+                "expected second inbound substream, got {:?}",
+                other.is_ready()
+            ),
+        }
+
+        // The limit of 2 un-acknowledged substreams has been reached: the third one is buffered
+        // rather than handed out, and `poll` reports no new event.
+        assert!(poll_once(&mut node).is_pending());
+        assert_eq!(node.buffered_inbound.len(), 1);
+
+        // Acknowledging one substream makes room for the buffered one to be delivered.
+        node.acknowledge_inbound();
+        match poll_once(&mut node) {
+            Poll::Ready(Ok(NodeEvent::InboundSubstream { .. })) => {}
+            other => panic!(
+                "expected buffered inbound substream, got {:?}",
+                other.is_ready()
+            ),
+        }
+        assert_eq!(node.buffered_inbound.len(), 0);
+    }
+
+    #[test]
+    fn excess_inbound_substreams_are_destroyed_once_buffer_is_full() {
+        let inbound = Arc::new(Mutex::new(VecDeque::new()));
+        let destroyed = Arc::new(Mutex::new(Vec::new()));
+        let muxer = FakeMuxer {
+            inbound: inbound.clone(),
+            destroyed: destroyed.clone(),
+        };
+        let mut node = NodeStream::new(muxer);
+        node.set_max_pending_inbound(1);
+        inbound.lock().unwrap().extend([1, 2, 3]);
+
+        // First substream is delivered, reaching the pending limit of 1.
+        assert!(poll_once(&mut node).is_ready());
+        // Second substream is buffered, reaching the buffered limit of 1.
+        assert!(poll_once(&mut node).is_pending());
+        assert_eq!(node.buffered_inbound.len(), 1);
+        // Third substream has nowhere to go: it must be destroyed instead of accumulating.
+        assert!(poll_once(&mut node).is_pending());
+        assert_eq!(node.buffered_inbound.len(), 1);
+        assert_eq!(&*destroyed.lock().unwrap(), &[3]);
+    }
+
+    #[test]
+    fn cancel_substream_removes_only_the_targeted_substream() {
+        let muxer = FakeMuxer::default();
+        let mut node = NodeStream::new(muxer);
+
+        let id_a = node.open_substream(1u32);
+        let id_b = node.open_substream(2u32);
+
+        // Cancelling `id_a` returns its user data and leaves `id_b` untouched.
+        assert_eq!(node.cancel_substream(id_a), Some(1));
+        assert_eq!(node.outbound_substreams.len(), 1);
+
+        // Cancelling it again, now that it is gone, does nothing.
+        assert_eq!(node.cancel_substream(id_a), None);
+
+        // `id_b` is unaffected and can still be cancelled on its own.
+        assert_eq!(node.cancel_substream(id_b), Some(2));
+        assert_eq!(node.outbound_substreams.len(), 0);
+    }
+
+    #[test]
+    fn cancel_substream_with_unknown_id_returns_none() {
+        let muxer = FakeMuxer::default();
+        let mut node = NodeStream::new(muxer);
+        let _ = node.open_substream(1u32);
+
+        let bogus_id = OutboundSubstreamId(12345);
+        assert_eq!(node.cancel_substream(bogus_id), None);
+        assert_eq!(node.outbound_substreams.len(), 1);
+    }
+}